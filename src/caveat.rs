@@ -0,0 +1,36 @@
+// A caveat restricts what a token authorizes. First-party caveats carry only
+// a predicate that a `Verifier` must accept; third-party caveats additionally
+// carry an encrypted verification id and a hint location for where the
+// discharge macaroon can be obtained.
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Predicate(pub Vec<u8>);
+
+#[derive(Clone)]
+pub struct Caveat {
+  pub predicate:       Predicate,
+  pub verification_id: Option<Vec<u8>>,
+  pub location:        Option<Vec<u8>>
+}
+
+impl Caveat {
+  pub fn new(predicate: Predicate) -> Caveat {
+    Caveat {
+      predicate:       predicate,
+      verification_id: None,
+      location:        None
+    }
+  }
+
+  pub fn third_party(predicate: Predicate, verification_id: Vec<u8>, location: Vec<u8>) -> Caveat {
+    Caveat {
+      predicate:       predicate,
+      verification_id: Some(verification_id),
+      location:        Some(location)
+    }
+  }
+
+  pub fn is_third_party(&self) -> bool {
+    self.verification_id.is_some()
+  }
+}
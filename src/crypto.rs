@@ -0,0 +1,186 @@
+// Abstracts the keyed-MAC and authenticated-encryption primitives the token
+// machinery needs so that signature derivation and third-party-caveat
+// encryption aren't nailed to one cipher suite. `HashType` and
+// `EncryptionType` enumerate the algorithms a suite is built from; each
+// concrete `CryptoSuite` impl also reports a one-byte `suite_id` that gets
+// embedded in the v2 wire format so `deserialize` can confirm it's reading
+// the backend it thinks it is.
+
+use std::slice::bytes;
+
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::aead::chacha20poly1305;
+use sodiumoxide::crypto::aead::aes256gcm;
+
+pub enum HashType {
+  HmacSha256
+}
+
+impl HashType {
+  // The nibble this hash contributes to a suite's `suite_id`. Keeping this
+  // next to the enum means `CryptoSuite::suite_id`'s default impl can never
+  // drift out of sync with what `hash_type`/`encryption_type` actually say.
+  fn id(&self) -> u8 {
+    match *self {
+      HashType::HmacSha256 => 0x01
+    }
+  }
+}
+
+pub enum EncryptionType {
+  Chacha20Poly1305,
+  AesGcm
+}
+
+impl EncryptionType {
+  fn id(&self) -> u8 {
+    match *self {
+      EncryptionType::Chacha20Poly1305 => 0x01,
+      EncryptionType::AesGcm           => 0x02
+    }
+  }
+}
+
+pub trait CryptoSuite {
+  fn hash_type() -> HashType;
+  fn encryption_type() -> EncryptionType;
+
+  fn mac_len() -> usize;
+  fn nonce_len() -> usize;
+
+  // `key`/`nonce` must be at least `mac_len`/`nonce_len` bytes; implementors
+  // should fail loudly (e.g. `assert!`) rather than let a short buffer slip
+  // into an unchecked slice index. Every call site within this crate only
+  // ever passes internally-generated, correctly-sized buffers, so this is
+  // a contract for other callers/impls of this `pub` trait, not a path
+  // `Token` exercises.
+  fn mac(key: &[u8], data: &[u8]) -> Vec<u8>;
+  fn seal(key: &[u8], nonce: &[u8], plaintext: &[u8]) -> Vec<u8>;
+
+  // Unlike `mac`/`seal`, a short `key`/`nonce` here is reported the same
+  // way as any other reason decryption can fail: `None`, not a panic --
+  // `open`'s signature already has room for failure, so it should use it.
+  fn open(key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+
+  // Embedded in the v2 wire format so `deserialize_v2` can refuse to read a
+  // macaroon back with the wrong `CryptoSuite`. Derived from `hash_type`
+  // and `encryption_type` rather than set independently, so the identifier
+  // can't silently drift from the algorithms it's supposed to describe.
+  fn suite_id() -> u8 {
+    (Self::hash_type().id() << 4) | Self::encryption_type().id()
+  }
+}
+
+// The original suite: HMAC-SHA256 for the MAC, ChaCha20-Poly1305 for
+// encrypting third-party caveat keys. This is `Token`'s default so existing
+// callers see no change in behavior.
+pub struct Sha256Hmac;
+
+impl CryptoSuite for Sha256Hmac {
+  fn hash_type() -> HashType { HashType::HmacSha256 }
+  fn encryption_type() -> EncryptionType { EncryptionType::Chacha20Poly1305 }
+
+  fn mac_len() -> usize { hmacsha256::TAGBYTES }
+  fn nonce_len() -> usize { chacha20poly1305::NONCEBYTES }
+
+  fn mac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    assert!(key.len() >= hmacsha256::KEYBYTES, "mac: key shorter than hmacsha256::KEYBYTES");
+
+    let mut key_bytes = [0u8; hmacsha256::KEYBYTES];
+    bytes::copy_memory(&mut key_bytes, &key[..hmacsha256::KEYBYTES]);
+
+    let hmacsha256::Tag(tag_bytes) = hmacsha256::authenticate(data, &hmacsha256::Key(key_bytes));
+    tag_bytes.to_vec()
+  }
+
+  fn seal(key: &[u8], nonce: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    assert!(key.len() >= chacha20poly1305::KEYBYTES, "seal: key shorter than chacha20poly1305::KEYBYTES");
+    assert!(nonce.len() >= chacha20poly1305::NONCEBYTES, "seal: nonce shorter than chacha20poly1305::NONCEBYTES");
+
+    let mut key_bytes = [0u8; chacha20poly1305::KEYBYTES];
+    bytes::copy_memory(&mut key_bytes, &key[..chacha20poly1305::KEYBYTES]);
+
+    let mut nonce_bytes = [0u8; chacha20poly1305::NONCEBYTES];
+    bytes::copy_memory(&mut nonce_bytes, &nonce[..chacha20poly1305::NONCEBYTES]);
+
+    chacha20poly1305::seal(
+      plaintext,
+      None,
+      &chacha20poly1305::Nonce(nonce_bytes),
+      &chacha20poly1305::Key(key_bytes)
+    )
+  }
+
+  fn open(key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    if key.len() < chacha20poly1305::KEYBYTES || nonce.len() < chacha20poly1305::NONCEBYTES {
+      return None;
+    }
+
+    let mut key_bytes = [0u8; chacha20poly1305::KEYBYTES];
+    bytes::copy_memory(&mut key_bytes, &key[..chacha20poly1305::KEYBYTES]);
+
+    let mut nonce_bytes = [0u8; chacha20poly1305::NONCEBYTES];
+    bytes::copy_memory(&mut nonce_bytes, &nonce[..chacha20poly1305::NONCEBYTES]);
+
+    chacha20poly1305::open(
+      ciphertext,
+      None,
+      &chacha20poly1305::Nonce(nonce_bytes),
+      &chacha20poly1305::Key(key_bytes)
+    ).ok()
+  }
+}
+
+// Same MAC as `Sha256Hmac`, but encrypts third-party caveat keys with
+// AES-256-GCM instead of ChaCha20-Poly1305 -- useful in environments that
+// standardize on AES.
+pub struct AesGcm;
+
+impl CryptoSuite for AesGcm {
+  fn hash_type() -> HashType { HashType::HmacSha256 }
+  fn encryption_type() -> EncryptionType { EncryptionType::AesGcm }
+
+  fn mac_len() -> usize { hmacsha256::TAGBYTES }
+  fn nonce_len() -> usize { aes256gcm::NONCEBYTES }
+
+  fn mac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    Sha256Hmac::mac(key, data)
+  }
+
+  fn seal(key: &[u8], nonce: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    assert!(key.len() >= aes256gcm::KEYBYTES, "seal: key shorter than aes256gcm::KEYBYTES");
+    assert!(nonce.len() >= aes256gcm::NONCEBYTES, "seal: nonce shorter than aes256gcm::NONCEBYTES");
+
+    let mut key_bytes = [0u8; aes256gcm::KEYBYTES];
+    bytes::copy_memory(&mut key_bytes, &key[..aes256gcm::KEYBYTES]);
+
+    let mut nonce_bytes = [0u8; aes256gcm::NONCEBYTES];
+    bytes::copy_memory(&mut nonce_bytes, &nonce[..aes256gcm::NONCEBYTES]);
+
+    aes256gcm::seal(
+      plaintext,
+      None,
+      &aes256gcm::Nonce(nonce_bytes),
+      &aes256gcm::Key(key_bytes)
+    )
+  }
+
+  fn open(key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    if key.len() < aes256gcm::KEYBYTES || nonce.len() < aes256gcm::NONCEBYTES {
+      return None;
+    }
+
+    let mut key_bytes = [0u8; aes256gcm::KEYBYTES];
+    bytes::copy_memory(&mut key_bytes, &key[..aes256gcm::KEYBYTES]);
+
+    let mut nonce_bytes = [0u8; aes256gcm::NONCEBYTES];
+    bytes::copy_memory(&mut nonce_bytes, &nonce[..aes256gcm::NONCEBYTES]);
+
+    aes256gcm::open(
+      ciphertext,
+      None,
+      &aes256gcm::Nonce(nonce_bytes),
+      &aes256gcm::Key(key_bytes)
+    ).ok()
+  }
+}
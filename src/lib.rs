@@ -3,31 +3,69 @@
 
 #![feature(core)]
 #![feature(collections)]
+#![feature(default_type_params)]
 
 use std::slice::bytes;
+use std::marker::PhantomData;
+use std::collections::HashSet;
 
 pub mod caveat;
 pub use caveat::{Caveat, Predicate};
-pub use sodiumoxide::crypto::auth::hmacsha256::{Key, Tag, TAGBYTES};
+
+pub mod verifier;
+pub use verifier::Verifier;
+
+pub mod crypto;
+pub use crypto::{CryptoSuite, HashType, EncryptionType, Sha256Hmac, AesGcm};
 
 extern crate sodiumoxide;
-use sodiumoxide::crypto::auth::hmacsha256::authenticate;
+use sodiumoxide::utils::memcmp;
+
+extern crate time;
 
 extern crate "rustc-serialize" as serialize;
 use serialize::base64::{self, FromBase64, ToBase64};
 
-// Macaroons personalize the HMAC key using this string
+// Macaroons personalize the MAC key using this string
 // "macaroons-key-generator" padded to 32-bytes with zeroes
 const KEY_GENERATOR: &'static [u8; 32] = b"macaroons-key-generator\0\0\0\0\0\0\0\0\0";
 
+// Used to bind a discharge macaroon's signature to the root token it
+// accompanies; the key itself carries no secret, only the MAC construction
+// matters here.
+const ZERO_KEY: &'static [u8; 32] = &[0u8; 32];
+
 const PACKET_PREFIX_LENGTH: usize = 4;
 const MAX_PACKET_LENGTH:    usize = 65535;
 
-pub struct Token {
+// v1 is the original textual packet format and has no leading version byte
+// and no field recording the `CryptoSuite`, so it is read back with whatever
+// suite the caller names and nothing checks that it matches. v2 is a compact
+// binary encoding; its first byte is always this marker so `deserialize`
+// can tell the formats apart and reject any version it doesn't recognize
+// instead of silently misparsing it.
+const FORMAT_VERSION_V2: u8 = 0x02;
+
+const FIELD_LOCATION:   u8 = 0x01;
+const FIELD_IDENTIFIER: u8 = 0x02;
+const FIELD_CID:        u8 = 0x03;
+const FIELD_VID:        u8 = 0x04;
+const FIELD_CL:         u8 = 0x05;
+const FIELD_SIGNATURE:  u8 = 0x06;
+const FIELD_SUITE:      u8 = 0x07;
+
+// Bounds how deep a chain of third-party discharges can recurse during
+// verification. Paired with the `visited` identifier set in
+// `chain_signature`, this turns both a discharge cycle and a merely very
+// long discharge chain into a clean rejection instead of a stack overflow.
+const MAX_DISCHARGE_DEPTH: usize = 32;
+
+pub struct Token<C: CryptoSuite = Sha256Hmac> {
   pub location:   Vec<u8>,
   pub identifier: Vec<u8>,
   pub caveats:    Vec<Caveat>,
-  pub tag:        Tag
+  pub tag:        Vec<u8>,
+  suite:          PhantomData<C>
 }
 
 struct Packet {
@@ -35,24 +73,35 @@ struct Packet {
   pub value: Vec<u8>
 }
 
-impl Token {
-  pub fn new(key: Vec<u8>, identifier: Vec<u8>, location: Vec<u8>) -> Token {
-    let Tag(personalized_key) = authenticate(&key, &Key(*KEY_GENERATOR));
-    let tag = authenticate(&identifier, &Key(personalized_key));
+impl<C: CryptoSuite> Token<C> {
+  pub fn new(key: Vec<u8>, identifier: Vec<u8>, location: Vec<u8>) -> Token<C> {
+    let personalized_key = C::mac(KEY_GENERATOR, &key);
+    let tag = C::mac(&personalized_key, &identifier);
 
     Token {
       location:   location,
       identifier: identifier,
       caveats:    Vec::new(),
-      tag:        tag
+      tag:        tag,
+      suite:      PhantomData
+    }
+  }
+
+  pub fn deserialize(macaroon: Vec<u8>) -> Result<Token<C>, &'static str> {
+    match macaroon.first() {
+      Some(&FORMAT_VERSION_V2) => Token::deserialize_v2(&macaroon[1..]),
+      // Anything below the printable-ASCII range used by the base64
+      // alphabet is a version byte we don't understand yet.
+      Some(&version) if version < 0x20 => Err("unrecognized macaroon format version"),
+      _ => Token::deserialize_v1(macaroon)
     }
   }
 
-  pub fn deserialize(macaroon: Vec<u8>) -> Result<Token, &'static str> {
+  fn deserialize_v1(macaroon: Vec<u8>) -> Result<Token<C>, &'static str> {
     let mut location:   Option<Vec<u8>> = None;
     let mut identifier: Option<Vec<u8>> = None;
     let mut caveats:    Vec<Caveat>     = Vec::new();
-    let mut tag:        Option<Tag>     = None;
+    let mut tag:        Option<Vec<u8>> = None;
 
     let token_data = match macaroon.as_slice().from_base64() {
       Ok(bytes) => bytes,
@@ -62,7 +111,7 @@ impl Token {
     let mut index: usize = 0;
 
     while index < token_data.len() {
-      let (packet, taken) = match Token::depacketize(&token_data, index) {
+      let (packet, taken) = match Token::<C>::depacketize(&token_data, index) {
         Ok((p, t))  => (p, t),
         Err(reason) => return Err(reason)
       };
@@ -73,15 +122,20 @@ impl Token {
         b"location"   => location   = Some(packet.value),
         b"identifier" => identifier = Some(packet.value),
         b"cid"        => caveats.push(Caveat::new(Predicate(packet.value))),
+        b"vid"        => match caveats.last_mut() {
+          Some(caveat) => caveat.verification_id = Some(packet.value),
+          None         => return Err("'vid' packet with no preceding 'cid'")
+        },
+        b"cl"         => match caveats.last_mut() {
+          Some(caveat) => caveat.location = Some(packet.value),
+          None         => return Err("'cl' packet with no preceding 'cid'")
+        },
         b"signature"  => {
-          if packet.value.len() != TAGBYTES {
+          if packet.value.len() != C::mac_len() {
             return Err("invalid signature length")
           }
 
-          let mut signature_bytes = [0u8; TAGBYTES];
-          bytes::copy_memory(&mut signature_bytes, &packet.value[..TAGBYTES]);
-
-          tag = Some(Tag(signature_bytes))
+          tag = Some(packet.value)
         },
         _ => return Err("unrecognized packet type")
       }
@@ -95,7 +149,8 @@ impl Token {
       location:   location.unwrap(),
       identifier: identifier.unwrap(),
       caveats:    caveats,
-      tag:        tag.unwrap()
+      tag:        tag.unwrap(),
+      suite:      PhantomData
     };
 
     Ok(token)
@@ -132,10 +187,194 @@ impl Token {
     Ok((packet, packet_length))
   }
 
-  pub fn add_caveat(&self, caveat: Caveat) -> Token {
-    let Tag(key_bytes) = self.tag;
+  // Re-derives the MAC chain from scratch and drives `verifier` over every
+  // first-party caveat predicate in order, bailing out as soon as one is
+  // rejected. Third-party caveats are discharged by locating a matching
+  // token in `discharges`, checking that it was bound to this root via
+  // `bind_for_request`, and recursively verifying it with the caveat key
+  // recovered from its `vid`. The recomputed tag is compared against
+  // `self.tag` in constant time so a caller can't learn how many leading
+  // bytes matched.
+  pub fn verify<V: Verifier>(&self, key: &[u8], verifier: &V, discharges: &[Token<C>]) -> bool {
+    let mut visited: HashSet<Vec<u8>> = HashSet::new();
+    visited.insert(self.identifier.clone());
+
+    let computed = match self.chain_signature(key, verifier, discharges, self, &mut visited) {
+      Some(computed) => computed,
+      None           => return false
+    };
+
+    if !memcmp(&computed, &self.tag) {
+      return false;
+    }
+
+    visited.clear();
+    visited.insert(self.identifier.clone());
+    self.commit_caveats(verifier, discharges, &mut visited);
+
+    true
+  }
+
+  // Mirrors the caveat walk in `chain_signature`, but runs only after the
+  // whole macaroon has verified. Calls `Verifier::commit` for every
+  // first-party predicate along the way so stateful verifiers (`Nonce`)
+  // record their side effects exactly once per successful verification.
+  fn commit_caveats<V: Verifier>(&self, verifier: &V, discharges: &[Token<C>], visited: &mut HashSet<Vec<u8>>) {
+    for caveat in self.caveats.iter() {
+      let Predicate(predicate_bytes) = caveat.predicate.clone();
+
+      match caveat.verification_id {
+        Some(_) => {
+          if visited.len() >= MAX_DISCHARGE_DEPTH {
+            return;
+          }
+
+          let discharge = match discharges.iter().find(|d| d.identifier == predicate_bytes) {
+            Some(discharge) => discharge,
+            None            => continue
+          };
+
+          if visited.insert(discharge.identifier.clone()) {
+            discharge.commit_caveats(verifier, discharges, visited);
+            visited.remove(&discharge.identifier);
+          }
+        },
+        None => verifier.commit(&predicate_bytes)
+      }
+    }
+  }
+
+  // Recomputes this token's MAC chain and returns its *unbound* signature,
+  // or `None` if a caveat failed. `root` is the token the caller originally
+  // called `verify` on: discharge macaroons are bound to it, so checking
+  // one means comparing `bind_tag(root, <its recomputed signature>)` against
+  // the bound signature it was actually presented with, rather than
+  // comparing the recomputed signature directly. `visited` tracks every
+  // discharge identifier already entered on this path so a discharge that
+  // (directly or transitively) references itself is rejected instead of
+  // recursing forever.
+  fn chain_signature<V: Verifier>(&self, key: &[u8], verifier: &V, discharges: &[Token<C>], root: &Token<C>, visited: &mut HashSet<Vec<u8>>) -> Option<Vec<u8>> {
+    let personalized_key = C::mac(KEY_GENERATOR, key);
+    let mut sig = C::mac(&personalized_key, &self.identifier);
+
+    for caveat in self.caveats.iter() {
+      let Predicate(predicate_bytes) = caveat.predicate.clone();
+
+      let satisfied = match caveat.verification_id {
+        Some(ref vid) => {
+          let caveat_key = match Token::<C>::open_verification_id(vid, &sig) {
+            Some(caveat_key) => caveat_key,
+            None             => return None
+          };
+
+          let discharge = match discharges.iter().find(|d| d.identifier == predicate_bytes) {
+            Some(discharge) => discharge,
+            None            => return None
+          };
+
+          if visited.len() >= MAX_DISCHARGE_DEPTH || !visited.insert(discharge.identifier.clone()) {
+            return None;
+          }
+
+          let result = match discharge.chain_signature(&caveat_key, verifier, discharges, root, visited) {
+            Some(computed) => {
+              let bound = Token::<C>::bind_tag(&root.tag, &computed);
+              memcmp(&bound, &discharge.tag)
+            },
+            None => false
+          };
+
+          visited.remove(&discharge.identifier);
+          result
+        },
+        None => verifier.verify(&predicate_bytes)
+      };
+
+      if !satisfied {
+        return None;
+      }
+
+      let mut sig_input = Vec::new();
+
+      if let Some(ref vid) = caveat.verification_id {
+        sig_input.push_all(vid);
+      }
+
+      sig_input.push_all(&predicate_bytes);
+
+      sig = C::mac(&sig, &sig_input);
+    }
+
+    Some(sig)
+  }
+
+  // Encrypts `caveat_key` under the token's current signature to produce a
+  // verification id, then folds `vid || cid` into the signature the same
+  // way `add_caveat` folds in a first-party predicate.
+  pub fn add_third_party_caveat(&self, caveat_key: &[u8], identifier: &[u8], location: &[u8]) -> Token<C> {
+    let nonce = sodiumoxide::randombytes::randombytes(C::nonce_len());
+    let ciphertext = C::seal(&self.tag, &nonce, caveat_key);
+
+    let mut vid = Vec::new();
+    vid.push_all(&nonce);
+    vid.push_all(&ciphertext);
+
+    let mut sig_input = vid.clone();
+    sig_input.push_all(identifier);
+
+    let tag = C::mac(&self.tag, &sig_input);
+
+    let caveat = Caveat::third_party(Predicate(identifier.to_vec()), vid, location.to_vec());
+
+    let mut new_caveats = self.caveats.to_vec();
+    new_caveats.push(caveat);
+
+    Token {
+      identifier: self.identifier.clone(),
+      location:   self.location.clone(),
+      caveats:    new_caveats,
+      tag:        tag,
+      suite:      PhantomData
+    }
+  }
+
+  fn open_verification_id(vid: &Vec<u8>, sig: &Vec<u8>) -> Option<Vec<u8>> {
+    let nonce_len = C::nonce_len();
+
+    if vid.len() < nonce_len {
+      return None;
+    }
+
+    let nonce = &vid[..nonce_len];
+    let ciphertext = &vid[nonce_len..];
+
+    C::open(sig, nonce, ciphertext)
+  }
+
+  // Rewrites a discharge macaroon's tag so it is cryptographically tied to
+  // the specific root token it accompanies, preventing a discharge minted
+  // for one request from being replayed alongside a different root.
+  pub fn bind_for_request(&self, root: &Token<C>) -> Token<C> {
+    Token {
+      location:   self.location.clone(),
+      identifier: self.identifier.clone(),
+      caveats:    self.caveats.clone(),
+      tag:        Token::<C>::bind_tag(&root.tag, &self.tag),
+      suite:      PhantomData
+    }
+  }
+
+  fn bind_tag(root_tag: &Vec<u8>, discharge_tag: &Vec<u8>) -> Vec<u8> {
+    let mut bind_input = Vec::new();
+    bind_input.push_all(root_tag);
+    bind_input.push_all(discharge_tag);
+
+    C::mac(ZERO_KEY, &bind_input)
+  }
+
+  pub fn add_caveat(&self, caveat: Caveat) -> Token<C> {
     let Predicate(predicate_bytes) = caveat.predicate.clone();
-    let tag = authenticate(&predicate_bytes, &Key(key_bytes));
+    let tag = C::mac(&self.tag, &predicate_bytes);
 
     let mut new_caveats = self.caveats.to_vec();
     new_caveats.push(caveat);
@@ -144,27 +383,36 @@ impl Token {
       identifier: self.identifier.clone(),
       location:   self.location.clone(),
       caveats:    new_caveats,
-      tag:        tag
+      tag:        tag,
+      suite:      PhantomData
     }
   }
 
+  // Produces the original v1 packet format, kept for compatibility with
+  // existing callers; see `serialize_v2` for the newer binary encoding.
+  // The tag is whatever this token's `C` computed, but v1 predates
+  // `CryptoSuite` and has no field to record which one was used.
   pub fn serialize(&self) -> Vec<u8> {
     // TODO: estimate capacity and use Vec::with_capacity
     let mut result: Vec<u8> = Vec::new();
 
-    Token::packetize(&mut result, "location",   &self.location);
-    Token::packetize(&mut result, "identifier", &self.identifier);
+    Token::<C>::packetize(&mut result, "location",   &self.location);
+    Token::<C>::packetize(&mut result, "identifier", &self.identifier);
 
     for caveat in self.caveats.iter() {
       let Predicate(predicate_bytes) = caveat.predicate.clone();
-      Token::packetize(&mut result, "cid", &predicate_bytes);
-    }
+      Token::<C>::packetize(&mut result, "cid", &predicate_bytes);
 
-    let Tag(signature_bytes) = self.tag;
-    let mut signature_vec = Vec::new();
-    signature_vec.push_all(&signature_bytes);
+      if let Some(ref vid) = caveat.verification_id {
+        Token::<C>::packetize(&mut result, "vid", vid);
+      }
+
+      if let Some(ref location) = caveat.location {
+        Token::<C>::packetize(&mut result, "cl", location);
+      }
+    }
 
-    Token::packetize(&mut result, "signature", &signature_vec);
+    Token::<C>::packetize(&mut result, "signature", &self.tag);
 
     result.to_base64(base64::URL_SAFE).into_bytes()
   }
@@ -182,4 +430,323 @@ impl Token {
     result.append(&mut value.clone());
     result.push('\n' as u8);
   }
+
+  // A compact alternative to `serialize`: each field is `[field-id][varint
+  // length][bytes]` instead of a `0xNNNN field value\n` text packet, grouped
+  // in the same suite/location/identifier/caveats/signature order. Prefixed
+  // with `FORMAT_VERSION_V2` so `deserialize` can tell it apart from the v1
+  // packet format, and records `C::suite_id()` so `deserialize_v2` can
+  // refuse to read it back with the wrong `CryptoSuite`.
+  pub fn serialize_v2(&self) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::new();
+    result.push(FORMAT_VERSION_V2);
+
+    Token::<C>::packetize_v2(&mut result, FIELD_SUITE, &vec![C::suite_id()]);
+    Token::<C>::packetize_v2(&mut result, FIELD_LOCATION,   &self.location);
+    Token::<C>::packetize_v2(&mut result, FIELD_IDENTIFIER, &self.identifier);
+
+    for caveat in self.caveats.iter() {
+      let Predicate(predicate_bytes) = caveat.predicate.clone();
+      Token::<C>::packetize_v2(&mut result, FIELD_CID, &predicate_bytes);
+
+      if let Some(ref vid) = caveat.verification_id {
+        Token::<C>::packetize_v2(&mut result, FIELD_VID, vid);
+      }
+
+      if let Some(ref location) = caveat.location {
+        Token::<C>::packetize_v2(&mut result, FIELD_CL, location);
+      }
+    }
+
+    Token::<C>::packetize_v2(&mut result, FIELD_SIGNATURE, &self.tag);
+
+    result
+  }
+
+  fn deserialize_v2(data: &[u8]) -> Result<Token<C>, &'static str> {
+    let mut location:   Option<Vec<u8>> = None;
+    let mut identifier: Option<Vec<u8>> = None;
+    let mut caveats:    Vec<Caveat>     = Vec::new();
+    let mut tag:        Option<Vec<u8>> = None;
+    let mut saw_suite:  bool            = false;
+
+    let mut index: usize = 0;
+
+    while index < data.len() {
+      let field_id = data[index];
+      index += 1;
+
+      let (length, length_bytes) = match Token::<C>::read_varint(data, index) {
+        Ok((length, taken)) => (length, taken),
+        Err(reason)         => return Err(reason)
+      };
+      index += length_bytes;
+
+      let field_end = match index.checked_add(length) {
+        Some(field_end) if field_end <= data.len() => field_end,
+        _                                           => return Err("truncated v2 field")
+      };
+
+      let value = data[index .. field_end].to_vec();
+      index = field_end;
+
+      match field_id {
+        FIELD_SUITE      => {
+          if value.len() != 1 || value[0] != C::suite_id() {
+            return Err("macaroon was serialized with a different crypto suite");
+          }
+
+          saw_suite = true;
+        },
+        FIELD_LOCATION   => location   = Some(value),
+        FIELD_IDENTIFIER => identifier = Some(value),
+        FIELD_CID        => caveats.push(Caveat::new(Predicate(value))),
+        FIELD_VID        => match caveats.last_mut() {
+          Some(caveat) => caveat.verification_id = Some(value),
+          None         => return Err("'vid' field with no preceding 'cid'")
+        },
+        FIELD_CL         => match caveats.last_mut() {
+          Some(caveat) => caveat.location = Some(value),
+          None         => return Err("'cl' field with no preceding 'cid'")
+        },
+        FIELD_SIGNATURE  => {
+          if value.len() != C::mac_len() {
+            return Err("invalid signature length");
+          }
+
+          tag = Some(value);
+        },
+        _ => return Err("unrecognized v2 field id")
+      }
+    }
+
+    if !saw_suite              { return Err("no 'suite' found"); }
+    if location   == None { return Err("no 'location' found"); }
+    if identifier == None { return Err("no 'identifier' found"); }
+    if tag        == None { return Err("no 'signature' found"); }
+
+    Ok(Token {
+      location:   location.unwrap(),
+      identifier: identifier.unwrap(),
+      caveats:    caveats,
+      tag:        tag.unwrap(),
+      suite:      PhantomData
+    })
+  }
+
+  fn packetize_v2(result: &mut Vec<u8>, field_id: u8, value: &Vec<u8>) {
+    result.push(field_id);
+    Token::<C>::write_varint(result, value.len());
+    result.push_all(value.as_slice());
+  }
+
+  // LEB128-style varint: 7 bits of payload per byte, high bit set on every
+  // byte but the last.
+  fn write_varint(result: &mut Vec<u8>, value: usize) {
+    let mut remaining = value;
+
+    loop {
+      let mut byte = (remaining & 0x7f) as u8;
+      remaining >>= 7;
+
+      if remaining != 0 {
+        byte |= 0x80;
+      }
+
+      result.push(byte);
+
+      if remaining == 0 {
+        break;
+      }
+    }
+  }
+
+  // LEB128-style varint, capped at 10 bytes: that's enough to hold any
+  // 64-bit length and keeps `shift` from ever reaching the width of `usize`,
+  // which would otherwise panic (debug) or silently wrap (release) on a
+  // malicious run of continuation bytes.
+  fn read_varint(data: &[u8], index: usize) -> Result<(usize, usize), &'static str> {
+    const MAX_VARINT_BYTES: usize = 10;
+
+    let mut value: usize = 0;
+    let mut shift: u32 = 0;
+    let mut taken: usize = 0;
+
+    loop {
+      if taken >= MAX_VARINT_BYTES {
+        return Err("varint too long");
+      }
+
+      let pos = match index.checked_add(taken) {
+        Some(pos) if pos < data.len() => pos,
+        _                             => return Err("truncated varint")
+      };
+
+      let byte = data[pos];
+      taken += 1;
+
+      value |= ((byte & 0x7f) as usize) << shift;
+
+      if byte & 0x80 == 0 {
+        return Ok((value, taken));
+      }
+
+      shift += 7;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use verifier::{Eq, Nonce};
+
+  fn new_token() -> Token<Sha256Hmac> {
+    Token::new(b"root-key".to_vec(), b"identifier".to_vec(), b"https://example.com/".to_vec())
+  }
+
+  #[test]
+  fn verify_accepts_a_token_with_no_caveats() {
+    let token = new_token();
+    assert!(token.verify(b"root-key", &Eq("unused", "unused"), &[]));
+  }
+
+  #[test]
+  fn verify_rejects_the_wrong_key() {
+    let token = new_token();
+    assert!(!token.verify(b"wrong-key", &Eq("unused", "unused"), &[]));
+  }
+
+  #[test]
+  fn verify_checks_first_party_caveats() {
+    let token = new_token().add_caveat(Caveat::new(Predicate(b"user = alice".to_vec())));
+
+    assert!(token.verify(b"root-key", &Eq("user", "alice"), &[]));
+    assert!(!token.verify(b"root-key", &Eq("user", "bob"), &[]));
+  }
+
+  #[test]
+  fn verify_does_not_burn_a_nonce_on_a_failed_attempt() {
+    // A failed `Token::verify` (wrong key, so the final tag check never
+    // passes) must not commit the nonce caveat it walked along the way --
+    // otherwise a bogus macaroon reusing a legitimate `nonce = ...` value
+    // would permanently lock out the real holder's subsequent, genuine
+    // verification. Exercises the fix through the real `Token::verify`/
+    // `commit_caveats` code path, not just `Nonce` in isolation.
+    let token = new_token().add_caveat(Caveat::new(Predicate(b"nonce = x".to_vec())));
+    let nonce_verifier = Nonce::new();
+
+    assert!(!token.verify(b"wrong-key", &nonce_verifier, &[]));
+    assert!(token.verify(b"root-key", &nonce_verifier, &[]));
+  }
+
+  #[test]
+  fn verify_discharges_third_party_caveats() {
+    let root = new_token().add_third_party_caveat(b"caveat-key", b"third-party-id", b"https://auth.example.com/");
+
+    let discharge: Token<Sha256Hmac> = Token::new(b"caveat-key".to_vec(), b"third-party-id".to_vec(), b"https://auth.example.com/".to_vec());
+    let discharge = discharge.bind_for_request(&root);
+
+    assert!(root.verify(b"root-key", &Eq("unused", "unused"), &[discharge]));
+  }
+
+  #[test]
+  fn verify_rejects_a_discharge_minted_with_the_wrong_caveat_key() {
+    let root = new_token().add_third_party_caveat(b"caveat-key", b"third-party-id", b"https://auth.example.com/");
+
+    let discharge: Token<Sha256Hmac> = Token::new(b"wrong-caveat-key".to_vec(), b"third-party-id".to_vec(), b"https://auth.example.com/".to_vec());
+    let discharge = discharge.bind_for_request(&root);
+
+    assert!(!root.verify(b"root-key", &Eq("unused", "unused"), &[discharge]));
+  }
+
+  #[test]
+  fn verify_rejects_a_discharge_that_references_itself() {
+    let root = new_token().add_third_party_caveat(b"caveat-key", b"cycle-id", b"https://auth.example.com/");
+
+    let inner: Token<Sha256Hmac> = Token::new(b"caveat-key".to_vec(), b"cycle-id".to_vec(), b"https://auth.example.com/".to_vec());
+    // A discharge that (nonsensically) carries a third-party caveat whose
+    // 'cid' is its own identifier -- i.e. it claims to be discharged by
+    // itself. It reuses `caveat-key`, so every re-entry opens the same 'vid'
+    // again; without the visited-set guard in `chain_signature` this would
+    // recurse forever instead of failing cleanly.
+    let inner = inner.add_third_party_caveat(b"caveat-key", b"cycle-id", b"https://auth.example.com/");
+    let discharge = inner.bind_for_request(&root);
+
+    assert!(!root.verify(b"root-key", &Eq("unused", "unused"), &[discharge]));
+  }
+
+  #[test]
+  fn serialize_v1_round_trips() {
+    let token = new_token().add_caveat(Caveat::new(Predicate(b"user = alice".to_vec())));
+
+    let bytes = token.serialize();
+    let parsed: Token<Sha256Hmac> = Token::deserialize(bytes).unwrap();
+
+    assert_eq!(parsed.identifier, token.identifier);
+    assert_eq!(parsed.location, token.location);
+    assert_eq!(parsed.tag, token.tag);
+    assert_eq!(parsed.caveats.len(), 1);
+  }
+
+  #[test]
+  fn serialize_v2_round_trips() {
+    let token = new_token()
+      .add_caveat(Caveat::new(Predicate(b"user = alice".to_vec())))
+      .add_third_party_caveat(b"caveat-key", b"third-party-id", b"https://auth.example.com/");
+
+    let bytes = token.serialize_v2();
+    let parsed: Token<Sha256Hmac> = Token::deserialize(bytes).unwrap();
+
+    assert_eq!(parsed.identifier, token.identifier);
+    assert_eq!(parsed.location, token.location);
+    assert_eq!(parsed.tag, token.tag);
+    assert_eq!(parsed.caveats.len(), 2);
+    assert_eq!(parsed.caveats[1].verification_id, token.caveats[1].verification_id);
+    assert_eq!(parsed.caveats[1].location, token.caveats[1].location);
+  }
+
+  #[test]
+  fn deserialize_v2_rejects_a_mismatched_suite() {
+    let token = new_token();
+    let bytes = token.serialize_v2();
+
+    let result: Result<Token<AesGcm>, _> = Token::deserialize(bytes);
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn deserialize_v2_rejects_a_missing_suite_field() {
+    // Hand-assemble a v2 macaroon with every mandatory field except 'suite'.
+    let mut bytes: Vec<u8> = vec![FORMAT_VERSION_V2];
+    Token::<Sha256Hmac>::packetize_v2(&mut bytes, FIELD_LOCATION, &b"loc".to_vec());
+    Token::<Sha256Hmac>::packetize_v2(&mut bytes, FIELD_IDENTIFIER, &b"id".to_vec());
+    Token::<Sha256Hmac>::packetize_v2(&mut bytes, FIELD_SIGNATURE, &vec![0u8; Sha256Hmac::mac_len()]);
+
+    let result: Result<Token<Sha256Hmac>, _> = Token::deserialize(bytes);
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn deserialize_v2_rejects_an_overlong_varint_instead_of_panicking() {
+    // FIELD_SIGNATURE followed by 11 continuation bytes: `read_varint` must
+    // bail out with an `Err` instead of shifting `value` past `usize`'s
+    // width.
+    let mut bytes: Vec<u8> = vec![FORMAT_VERSION_V2, FIELD_SIGNATURE];
+    bytes.push_all(&[0xffu8; 11]);
+
+    let result: Result<Token<Sha256Hmac>, _> = Token::deserialize(bytes);
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn deserialize_v2_rejects_a_length_that_would_overflow_past_the_buffer() {
+    // A varint length close to usize::MAX must not wrap `index + length`
+    // back below `index` and slip past the bounds check.
+    let mut bytes: Vec<u8> = vec![FORMAT_VERSION_V2, FIELD_SIGNATURE];
+    Token::<Sha256Hmac>::write_varint(&mut bytes, std::usize::MAX - 1);
+
+    let result: Result<Token<Sha256Hmac>, _> = Token::deserialize(bytes);
+    assert!(result.is_err());
+  }
 }
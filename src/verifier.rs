@@ -1,8 +1,19 @@
 use std::sync::Arc;
 use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use time::Tm;
 
 pub trait Verifier {
     fn verify(&self, caveat: &[u8]) -> bool;
+
+    // Called once per first-party predicate that was satisfied, but only
+    // after the *entire* macaroon has verified -- recomputed tag included.
+    // Stateful verifiers like `Nonce` use this to commit side effects that
+    // must not happen for a macaroon whose caveats merely looked
+    // satisfiable along the way but whose signature didn't check out.
+    fn commit(&self, _caveat: &[u8]) {}
 }
 
 // Pointer primitives
@@ -11,24 +22,40 @@ impl<'a, V: Verifier> Verifier for &'a V {
     fn verify(&self, caveat: &[u8]) -> bool {
         (**self).verify(caveat)
     }
+
+    fn commit(&self, caveat: &[u8]) {
+        (**self).commit(caveat)
+    }
 }
 
 impl<V: Verifier> Verifier for Box<V> {
     fn verify(&self, caveat: &[u8]) -> bool {
         (**self).verify(caveat)
     }
+
+    fn commit(&self, caveat: &[u8]) {
+        (**self).commit(caveat)
+    }
 }
 
 impl<V: Verifier> Verifier for Rc<V> {
     fn verify(&self, caveat: &[u8]) -> bool {
         (**self).verify(caveat)
     }
+
+    fn commit(&self, caveat: &[u8]) {
+        (**self).commit(caveat)
+    }
 }
 
 impl<V: Verifier> Verifier for Arc<V> {
     fn verify(&self, caveat: &[u8]) -> bool {
         (**self).verify(caveat)
     }
+
+    fn commit(&self, caveat: &[u8]) {
+        (**self).commit(caveat)
+    }
 }
 
 // Func
@@ -78,6 +105,16 @@ impl<V1: Verifier, V2: Verifier> Verifier for LinkedVerifier<V1, V2> {
            self.verifier1.verify(caveat)
         || self.verifier2.verify(caveat)
     }
+
+    fn commit(&self, caveat: &[u8]) {
+        // Commit whichever branch actually accepted the predicate; a
+        // branch that would've rejected it has nothing to commit.
+        if self.verifier1.verify(caveat) {
+            self.verifier1.commit(caveat);
+        } else if self.verifier2.verify(caveat) {
+            self.verifier2.commit(caveat);
+        }
+    }
 }
 
 // Eq
@@ -109,3 +146,207 @@ impl<T: Verifier> LinkVerifier for T {
         LinkedVerifier::from(verifier, self)
     }
 }
+
+// Shared by `TimeBefore` and `Expires`: strips `prefix` off the caveat,
+// parses the remainder as an RFC 3339-ish timestamp, and checks it's still
+// in the future relative to `now`. Malformed or mismatched-prefix caveats
+// are rejected rather than treated as an error, same as every other
+// `Verifier` impl in this module.
+fn before_deadline(caveat: &[u8], prefix: &[u8], now: Tm) -> bool {
+    if caveat.len() <= prefix.len() || &caveat[..prefix.len()] != prefix {
+        return false;
+    }
+
+    let text = match ::std::str::from_utf8(&caveat[prefix.len()..]) {
+        Ok(text) => text,
+        Err(_)   => return false,
+    };
+
+    let deadline = match time::strptime(text, "%Y-%m-%dT%H:%M:%SZ") {
+        Ok(tm)  => tm,
+        Err(_)  => return false,
+    };
+
+    now.to_timespec() < deadline.to_timespec()
+}
+
+// TimeBefore
+
+// Accepts a `time < <rfc3339>` predicate and checks it against a clock.
+// The clock is injectable so tests aren't at the mercy of the wall clock;
+// `TimeBefore::new` wires up `time::now_utc` for real use.
+pub struct TimeBefore<F: Fn() -> Tm> {
+    now: F,
+}
+
+impl TimeBefore<fn() -> Tm> {
+    pub fn new() -> Self {
+        TimeBefore { now: time::now_utc }
+    }
+}
+
+impl<F: Fn() -> Tm> TimeBefore<F> {
+    pub fn with_clock(now: F) -> Self {
+        TimeBefore { now: now }
+    }
+}
+
+impl<F: Fn() -> Tm> Verifier for TimeBefore<F> {
+    fn verify(&self, caveat: &[u8]) -> bool {
+        before_deadline(caveat, b"time < ", (self.now)())
+    }
+}
+
+// Expires
+
+// Accepts a `expires = <rfc3339>` predicate -- the form `Token::add_caveat`
+// callers use to stamp a token's own expiry, as distinct from the more
+// general `time < <rfc3339>` caveats a third party might impose via
+// `TimeBefore`. Same deadline check, different predicate vocabulary.
+pub struct Expires<F: Fn() -> Tm> {
+    now: F,
+}
+
+impl Expires<fn() -> Tm> {
+    pub fn new() -> Self {
+        Expires { now: time::now_utc }
+    }
+}
+
+impl<F: Fn() -> Tm> Expires<F> {
+    pub fn with_clock(now: F) -> Self {
+        Expires { now: now }
+    }
+}
+
+impl<F: Fn() -> Tm> Verifier for Expires<F> {
+    fn verify(&self, caveat: &[u8]) -> bool {
+        before_deadline(caveat, b"expires = ", (self.now)())
+    }
+}
+
+// Nonce
+
+// Accepts a `nonce = <value>` predicate the first time it's seen and
+// rejects the token on any replay of the same nonce. `verify` only checks
+// membership -- the nonce isn't recorded as seen until `commit` runs, which
+// `Token::verify` only does once the whole macaroon (including the final
+// tag check) has verified. Otherwise a bogus macaroon that merely reuses a
+// legitimate nonce value would burn it on a failed attempt, locking out the
+// real holder's subsequent genuine verification.
+pub struct Nonce {
+    seen: RefCell<HashSet<Vec<u8>>>,
+}
+
+impl Nonce {
+    pub fn new() -> Nonce {
+        Nonce { seen: RefCell::new(HashSet::new()) }
+    }
+
+    fn value<'a>(caveat: &'a [u8]) -> Option<&'a [u8]> {
+        let prefix = b"nonce = ";
+
+        if caveat.len() <= prefix.len() || &caveat[..prefix.len()] != prefix {
+            return None;
+        }
+
+        Some(&caveat[prefix.len()..])
+    }
+}
+
+impl Verifier for Nonce {
+    fn verify(&self, caveat: &[u8]) -> bool {
+        match Nonce::value(caveat) {
+            Some(value) => !self.seen.borrow().contains(value),
+            None        => false
+        }
+    }
+
+    fn commit(&self, caveat: &[u8]) {
+        if let Some(value) = Nonce::value(caveat) {
+            self.seen.borrow_mut().insert(value.to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time;
+
+    #[test]
+    fn eq_matches_only_the_exact_predicate() {
+        let verifier = Eq("user", "alice");
+
+        assert!(verifier.verify(b"user = alice"));
+        assert!(!verifier.verify(b"user = bob"));
+        assert!(!verifier.verify(b"user = alicia"));
+    }
+
+    #[test]
+    fn link_accepts_a_predicate_satisfied_by_either_side() {
+        let verifier = Eq("user", "alice").link(Eq("user", "bob"));
+
+        assert!(verifier.verify(b"user = alice"));
+        assert!(verifier.verify(b"user = bob"));
+        assert!(!verifier.verify(b"user = carol"));
+    }
+
+    #[test]
+    fn time_before_accepts_a_deadline_the_clock_has_not_reached() {
+        let now = time::strptime("2020-01-01T00:00:00Z", "%Y-%m-%dT%H:%M:%SZ").unwrap();
+        let verifier = TimeBefore::with_clock(move || now);
+
+        assert!(verifier.verify(b"time < 2020-06-01T00:00:00Z"));
+        assert!(!verifier.verify(b"time < 2019-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn time_before_rejects_a_malformed_predicate() {
+        let verifier = TimeBefore::new();
+        assert!(!verifier.verify(b"not a time predicate"));
+    }
+
+    #[test]
+    fn expires_accepts_a_deadline_the_clock_has_not_reached() {
+        let now = time::strptime("2020-01-01T00:00:00Z", "%Y-%m-%dT%H:%M:%SZ").unwrap();
+        let verifier = Expires::with_clock(move || now);
+
+        assert!(verifier.verify(b"expires = 2020-06-01T00:00:00Z"));
+        assert!(!verifier.verify(b"expires = 2019-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn expires_and_time_before_use_distinct_predicates() {
+        let now = time::strptime("2020-01-01T00:00:00Z", "%Y-%m-%dT%H:%M:%SZ").unwrap();
+
+        // `Expires` doesn't accept `TimeBefore`'s predicate text, and vice
+        // versa -- they're different caveat vocabularies, not the same
+        // check under two names.
+        assert!(!Expires::with_clock(move || now).verify(b"time < 2020-06-01T00:00:00Z"));
+        assert!(!TimeBefore::with_clock(move || now).verify(b"expires = 2020-06-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn nonce_rejects_a_value_once_committed() {
+        let verifier = Nonce::new();
+        let caveat = b"nonce = abc";
+
+        assert!(verifier.verify(caveat));
+        verifier.commit(caveat);
+        assert!(!verifier.verify(caveat));
+    }
+
+    #[test]
+    fn nonce_is_not_burned_by_checking_alone() {
+        // `Token::verify` only calls `commit` once the whole macaroon has
+        // verified; a caveat that's merely checked along the way (e.g. a
+        // verification attempt that fails a later caveat) must not burn the
+        // nonce for a subsequent, genuine attempt.
+        let verifier = Nonce::new();
+        let caveat = b"nonce = abc";
+
+        assert!(verifier.verify(caveat));
+        assert!(verifier.verify(caveat));
+    }
+}